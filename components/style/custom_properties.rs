@@ -2,42 +2,279 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use cssparser::{Parser, Token, SourcePosition};
+use cssparser::{Parser, Token, SourcePosition, TokenSerializationType};
 use properties::DeclaredValue;
+use std::borrow::Borrow;
 use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::iter::FromIterator;
+use std::slice;
 use std::sync::Arc;
 use string_cache::Atom;
 
+/// A `Hasher` for keys that are cheap to hash, like `Atom`, whose `Hash`
+/// implementation writes out an already-computed hash instead of hashing
+/// their bytes. Custom property names are looked up repeatedly (by
+/// `cascade`, `find_cycles`, and the `var()` substitution functions) while
+/// resolving a single element's style, so skipping the general-purpose
+/// hasher's mixing step for them is a meaningful win.
+#[derive(Default)]
+pub struct PrecomputedHasher(u64);
+
+impl Hasher for PrecomputedHasher {
+    fn write(&mut self, _bytes: &[u8]) {
+        unreachable!("should only be hashing an already-hashed Atom")
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A `HashMap` for keys whose `Hash` implementation is backed by
+/// `PrecomputedHasher`.
+pub type PrecomputedHashMap<K, V> = HashMap<K, V, BuildHasherDefault<PrecomputedHasher>>;
+
+/// A `HashSet` for keys whose `Hash` implementation is backed by
+/// `PrecomputedHasher`.
+pub type PrecomputedHashSet<K> = HashSet<K, BuildHasherDefault<PrecomputedHasher>>;
+
+/// A map that, unlike `HashMap`, preserves the order in which keys were
+/// first inserted when iterated over.
+///
+/// Custom properties need this because the order observable from script
+/// (through `getComputedStyle()`/`cssText`) is author-declaration order,
+/// which a `HashMap` can't give us.
+pub struct OrderedMap<K, V>
+    where K: Eq + Hash
+{
+    /// Underlying storage, with no guarantees about iteration order.
+    values: PrecomputedHashMap<K, V>,
+    /// Keys, in the order they were first inserted.
+    index: Vec<K>,
+}
+
+impl<K, V> OrderedMap<K, V>
+    where K: Eq + Hash
+{
+    /// Creates a new empty map.
+    pub fn new() -> Self {
+        OrderedMap {
+            values: PrecomputedHashMap::default(),
+            index: Vec::new(),
+        }
+    }
+
+    /// Insert a value into the map, keeping the old position in the index
+    /// if the key was already present.
+    pub fn insert(&mut self, key: K, value: V)
+        where K: Clone
+    {
+        if !self.values.contains_key(&key) {
+            self.index.push(key.clone());
+        }
+        self.values.insert(key, value);
+    }
+
+    /// Get a reference to the value corresponding to `key`.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Eq + Hash
+    {
+        self.values.get(key)
+    }
+
+    /// Returns whether `key` has a value in the map.
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Eq + Hash
+    {
+        self.values.contains_key(key)
+    }
+
+    /// Remove the value corresponding to `key`, if any.
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Eq + Hash
+    {
+        if let Some(position) = self.index.iter().position(|k| k.borrow() == key) {
+            self.index.remove(position);
+        }
+        self.values.remove(key)
+    }
+
+    /// Iterate over the keys of this map, in insertion order.
+    pub fn keys(&self) -> slice::Iter<K> {
+        self.index.iter()
+    }
+
+    /// Iterate over the entries of this map, in insertion order. The
+    /// returned iterator also supports iterating in reverse (`.rev()`).
+    pub fn iter(&self) -> OrderedMapIter<K, V> {
+        OrderedMapIter {
+            index_iter: self.index.iter(),
+            values: &self.values,
+        }
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for OrderedMap<K, V>
+    where K: Eq + Hash + Clone
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = OrderedMap::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a OrderedMap<K, V>
+    where K: Eq + Hash
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = OrderedMapIter<'a, K, V>;
+
+    fn into_iter(self) -> OrderedMapIter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// An iterator over the entries of an `OrderedMap`, in insertion order.
+pub struct OrderedMapIter<'a, K: 'a, V: 'a> {
+    index_iter: slice::Iter<'a, K>,
+    values: &'a PrecomputedHashMap<K, V>,
+}
+
+impl<'a, K, V> Iterator for OrderedMapIter<'a, K, V>
+    where K: Eq + Hash
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.index_iter.next().map(|key| (key, self.values.get(key).unwrap()))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for OrderedMapIter<'a, K, V>
+    where K: Eq + Hash
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.index_iter.next_back().map(|key| (key, self.values.get(key).unwrap()))
+    }
+}
+
+/// A map from custom property names to their values, as inherited or
+/// substituted for `getComputedStyle()`/`cssText`.
+pub type CustomPropertiesMap = OrderedMap<Atom, ComputedValue>;
+
+/// A fully var()-substituted custom property value, together with the
+/// serialization type of its first and last token.
+///
+/// Keeping these around (rather than re-tokenizing the string every time it
+/// is substituted into something else) lets `substitute_block` decide
+/// whether it needs to insert an empty comment (`/**/`) between this value
+/// and whatever follows it, so that re-tokenizing the result doesn't fuse
+/// two tokens that were never meant to be adjacent.
+#[derive(Clone)]
+pub struct ComputedValue {
+    pub css: String,
+    first_token_type: TokenSerializationType,
+    last_token_type: TokenSerializationType,
+}
+
 pub struct Value {
     /// In CSS syntax
     value: String,
 
+    /// The serialization type of this value's first token. See
+    /// `ComputedValue`.
+    first_token_type: TokenSerializationType,
+
+    /// The serialization type of this value's last token. See
+    /// `ComputedValue`.
+    last_token_type: TokenSerializationType,
+
     /// Custom property names in var() functions. Do not include the `--` prefix.
-    references: HashSet<Atom>,
+    references: PrecomputedHashSet<Atom>,
 }
 
 pub struct BorrowedValue<'a> {
     value: &'a str,
-    references: Option<&'a HashSet<Atom>>,
+    first_token_type: TokenSerializationType,
+    last_token_type: TokenSerializationType,
+    references: Option<&'a PrecomputedHashSet<Atom>>,
+}
+
+/// The unparsed value of a non-custom-property declaration that contains
+/// one or more `var()` references.
+///
+/// Properties other than custom properties can't be substituted until the
+/// custom property cascade has finished, so parsing such a declaration is
+/// split in two: capture its token stream and the custom properties it
+/// references with `parse_non_custom_with_var`, stash the result in
+/// `DeclaredValue::WithVariables` (defined in `properties.rs`), then once
+/// `finish_cascade` has resolved this element's custom properties, call
+/// `substitute` to get the text to hand to the property's real parser.
+pub struct UnparsedValue {
+    css: String,
+    first_token_type: TokenSerializationType,
+    references: PrecomputedHashSet<Atom>,
+}
+
+/// https://drafts.csswg.org/css-variables/#using-variables
+///
+/// Parse the value of a non-custom property, as a `var()`-using token
+/// stream to be substituted later, if it references any custom property.
+/// Returns `Ok(None)` when there are no `var()` references at all, so that
+/// callers can fall back to parsing the value directly as usual.
+pub fn parse_non_custom_with_var(input: &mut Parser) -> Result<Option<UnparsedValue>, ()> {
+    let start = input.position();
+    let mut references = PrecomputedHashSet::default();
+    let (first_token_type, _) = try!(parse_declaration_value(input, &mut references));
+    if references.is_empty() {
+        return Ok(None)
+    }
+    Ok(Some(UnparsedValue {
+        css: input.slice_from(start).to_owned(),
+        first_token_type: first_token_type,
+        references: references,
+    }))
 }
 
 pub fn parse(input: &mut Parser) -> Result<Value, ()> {
     let start = input.position();
-    let mut references = HashSet::new();
-    try!(parse_declaration_value(input, &mut references));
+    let mut references = PrecomputedHashSet::default();
+    let (first_token_type, last_token_type) =
+        try!(parse_declaration_value(input, &mut references));
     Ok(Value {
         value: input.slice_from(start).to_owned(),
+        first_token_type: first_token_type,
+        last_token_type: last_token_type,
         references: references,
     })
 }
 
 /// https://drafts.csswg.org/css-syntax-3/#typedef-declaration-value
-fn parse_declaration_value(input: &mut Parser, references: &mut HashSet<Atom>) -> Result<(), ()> {
+///
+/// Returns the serialization types of the value's first and last token, so
+/// that callers know what it might fuse with once substituted elsewhere.
+fn parse_declaration_value(input: &mut Parser, references: &mut PrecomputedHashSet<Atom>)
+                           -> Result<(TokenSerializationType, TokenSerializationType), ()> {
     if input.is_exhausted() {
         // Need at least one token
         return Err(())
     }
+    let mut first_token_type = None;
+    let mut last_token_type = TokenSerializationType::nothing();
     while let Ok(token) = input.next() {
+        let this_token_type = token.serialization_type();
+        if first_token_type.is_none() {
+            first_token_type = Some(this_token_type);
+        }
+        last_token_type = this_token_type;
         match token {
             Token::BadUrl |
             Token::BadString |
@@ -54,6 +291,9 @@ fn parse_declaration_value(input: &mut Parser, references: &mut HashSet<Atom>) -
                 try!(input.parse_nested_block(|input| {
                     parse_var_function(input, references)
                 }));
+                // The nested block ends in a closing parenthesis, which
+                // never needs a separator before whatever comes next.
+                last_token_type = TokenSerializationType::nothing();
             }
 
             Token::Function(_) |
@@ -63,17 +303,18 @@ fn parse_declaration_value(input: &mut Parser, references: &mut HashSet<Atom>) -
                 try!(input.parse_nested_block(|input| {
                     parse_declaration_value_block(input, references)
                 }));
+                last_token_type = TokenSerializationType::nothing();
             }
 
             _ => {}
         }
     }
-    Ok(())
+    Ok((first_token_type.unwrap(), last_token_type))
 }
 
 /// Like parse_declaration_value,
 /// but accept `!` and `;` since they are only invalid at the top level
-fn parse_declaration_value_block(input: &mut Parser, references: &mut HashSet<Atom>)
+fn parse_declaration_value_block(input: &mut Parser, references: &mut PrecomputedHashSet<Atom>)
                                  -> Result<(), ()> {
     while let Ok(token) = input.next() {
         match token {
@@ -107,7 +348,7 @@ fn parse_declaration_value_block(input: &mut Parser, references: &mut HashSet<At
 }
 
 // If the var function is valid, return Ok((custom_property_name, fallback))
-fn parse_var_function<'i, 't>(input: &mut Parser<'i, 't>, references: &mut HashSet<Atom>)
+fn parse_var_function<'i, 't>(input: &mut Parser<'i, 't>, references: &mut PrecomputedHashSet<Atom>)
                               -> Result<(), ()> {
     // https://drafts.csswg.org/css-variables/#typedef-custom-property-name
     let name = try!(input.expect_ident());
@@ -125,9 +366,9 @@ fn parse_var_function<'i, 't>(input: &mut Parser<'i, 't>, references: &mut HashS
 
 /// Add one custom property declaration to a map,
 /// unless another with the same name was already there.
-pub fn cascade<'a>(custom_properties: &mut Option<HashMap<&'a Atom, BorrowedValue<'a>>>,
-                   inherited_custom_properties: &'a Option<Arc<HashMap<Atom, String>>>,
-                   seen: &mut HashSet<&'a Atom>,
+pub fn cascade<'a>(custom_properties: &mut Option<OrderedMap<&'a Atom, BorrowedValue<'a>>>,
+                   inherited_custom_properties: &'a Option<Arc<CustomPropertiesMap>>,
+                   seen: &mut PrecomputedHashSet<&'a Atom>,
                    name: &'a Atom,
                    value: &'a DeclaredValue<Value>) {
     let was_not_already_present = seen.insert(name);
@@ -137,9 +378,14 @@ pub fn cascade<'a>(custom_properties: &mut Option<HashMap<&'a Atom, BorrowedValu
             None => {
                 *custom_properties = Some(match *inherited_custom_properties {
                     Some(ref inherited) => inherited.iter().map(|(key, value)| {
-                        (key, BorrowedValue { value: &value, references: None })
+                        (key, BorrowedValue {
+                            value: &value.css,
+                            first_token_type: value.first_token_type,
+                            last_token_type: value.last_token_type,
+                            references: None,
+                        })
                     }).collect(),
-                    None => HashMap::new(),
+                    None => OrderedMap::new(),
                 });
                 custom_properties.as_mut().unwrap()
             }
@@ -148,6 +394,8 @@ pub fn cascade<'a>(custom_properties: &mut Option<HashMap<&'a Atom, BorrowedValu
             DeclaredValue::Value(ref value) => {
                 map.insert(name, BorrowedValue {
                     value: &value.value,
+                    first_token_type: value.first_token_type,
+                    last_token_type: value.last_token_type,
                     references: Some(&value.references),
                 });
             }
@@ -155,22 +403,26 @@ pub fn cascade<'a>(custom_properties: &mut Option<HashMap<&'a Atom, BorrowedValu
                 map.remove(&name);
             }
             DeclaredValue::Inherit => {}  // The inherited value is what we already have.
+            DeclaredValue::WithVariables(_) => {
+                unreachable!("Custom property declarations should never be DeclaredValue::WithVariables")
+            }
         }
     }
 }
 
-pub fn finish_cascade(custom_properties: Option<HashMap<&Atom, BorrowedValue>>,
-                      inherited_custom_properties: &Option<Arc<HashMap<Atom, String>>>)
-                      -> Option<Arc<HashMap<Atom, String>>> {
+pub fn finish_cascade(custom_properties: Option<OrderedMap<&Atom, BorrowedValue>>,
+                      inherited_custom_properties: &Option<Arc<CustomPropertiesMap>>)
+                      -> Option<Arc<CustomPropertiesMap>> {
     if let Some(custom_properties) = custom_properties {
-        let mut invalid = HashSet::new();
+        let mut invalid = PrecomputedHashSet::default();
         find_cycles(&custom_properties, &mut invalid);
-        let mut substituted_map = HashMap::new();
+        let mut substituted_map = OrderedMap::new();
         for (&name, value) in &custom_properties {
             // If this value is invalid at computed time it won’t be inserted in substituted_map.
             // Nothing else to do.
             let _ = substitute_one(
-                name, value, &custom_properties, None, &mut substituted_map, &mut invalid);
+                name, value, &custom_properties, None, TokenSerializationType::nothing(),
+                &mut substituted_map, &mut invalid);
         }
         Some(Arc::new(substituted_map))
     } else {
@@ -180,18 +432,65 @@ pub fn finish_cascade(custom_properties: Option<HashMap<&Atom, BorrowedValue>>,
     }
 }
 
+/// Replace the `var()` references in the value captured by
+/// `parse_non_custom_with_var` with the custom property values that were
+/// resolved for this element by `finish_cascade`, and return the resulting
+/// CSS text for the caller to re-parse with the property's own parser.
+///
+/// A custom property that's missing or invalid (including cyclic, per
+/// `finish_cascade`) simply isn't in `custom_properties`, so a `var()`
+/// referencing it is an error unless it has a fallback — per
+/// https://drafts.csswg.org/css-variables/#invalid-at-computed-value-time
+/// the caller should then use this declaration's inherited value if the
+/// property is inherited, or its initial value otherwise.
+pub fn substitute(value: &UnparsedValue, custom_properties: &Option<Arc<CustomPropertiesMap>>)
+                  -> Result<String, ()> {
+    debug_assert!(!value.references.is_empty());
+
+    // `substitute_block` only cares about the already-fully-substituted
+    // values of the custom properties it looks up, so wrap them in
+    // `BorrowedValue`s with no references of their own (there's nothing
+    // left to substitute in them).
+    let custom_properties: OrderedMap<&Atom, BorrowedValue> = match *custom_properties {
+        Some(ref map) => map.iter().map(|(name, value)| {
+            (name, BorrowedValue {
+                value: &value.css,
+                first_token_type: value.first_token_type,
+                last_token_type: value.last_token_type,
+                references: None,
+            })
+        }).collect(),
+        None => OrderedMap::new(),
+    };
+
+    let mut substituted_map = OrderedMap::new();
+    let mut invalid = PrecomputedHashSet::default();
+    let mut substituted = String::new();
+    let mut input = Parser::new(&value.css);
+    let mut start = input.position();
+    let last_token_type = try!(substitute_block(
+        &custom_properties, &mut input, &mut start, &mut substituted,
+        TokenSerializationType::nothing(), &mut substituted_map, &mut invalid));
+    let tail = input.slice_from(start);
+    if !tail.is_empty() {
+        let (tail_first, _) = tokenize_bounds(tail);
+        push_with_separator(&mut substituted, tail, last_token_type, tail_first);
+    }
+    Ok(substituted)
+}
+
 /// https://drafts.csswg.org/css-variables/#cycles
-fn find_cycles(map: &HashMap<&Atom, BorrowedValue>, invalid: &mut HashSet<Atom>) {
-    let mut visited = HashSet::new();
+fn find_cycles(map: &OrderedMap<&Atom, BorrowedValue>, invalid: &mut PrecomputedHashSet<Atom>) {
+    let mut visited = PrecomputedHashSet::default();
     let mut stack = Vec::new();
     for name in map.keys() {
         walk(map, name, &mut stack, &mut visited, invalid);
 
-        fn walk<'a>(map: &HashMap<&'a Atom, BorrowedValue<'a>>,
+        fn walk<'a>(map: &OrderedMap<&'a Atom, BorrowedValue<'a>>,
                     name: &'a Atom,
                     stack: &mut Vec<&'a Atom>,
-                    visited: &mut HashSet<&'a Atom>,
-                    invalid: &mut HashSet<Atom>) {
+                    visited: &mut PrecomputedHashSet<&'a Atom>,
+                    invalid: &mut PrecomputedHashSet<Atom>) {
             let was_not_already_present = visited.insert(name);
             if !was_not_already_present {
                 return
@@ -216,70 +515,151 @@ fn find_cycles(map: &HashMap<&Atom, BorrowedValue>, invalid: &mut HashSet<Atom>)
     }
 }
 
+/// Re-tokenize an already-substituted value to find the serialization type
+/// of its first and last token, treating function calls and other blocks
+/// as opaque (they always end in an unambiguous closing delimiter).
+fn tokenize_bounds(css: &str) -> (TokenSerializationType, TokenSerializationType) {
+    let mut input = Parser::new(css);
+    let mut first_token_type = None;
+    let mut last_token_type = TokenSerializationType::nothing();
+    while let Ok(token) = input.next() {
+        let this_token_type = token.serialization_type();
+        if first_token_type.is_none() {
+            first_token_type = Some(this_token_type);
+        }
+        last_token_type = match token {
+            Token::Function(_) |
+            Token::ParenthesisBlock |
+            Token::CurlyBracketBlock |
+            Token::SquareBracketBlock => TokenSerializationType::nothing(),
+            _ => this_token_type,
+        };
+    }
+    (first_token_type.unwrap_or_else(TokenSerializationType::nothing), last_token_type)
+}
+
+/// Push `css` onto `substituted`, inserting an empty comment (`/**/`) first
+/// if the token at the end of `substituted` and the token at the start of
+/// `css` would otherwise re-tokenize into something else.
+fn push_with_separator(substituted: &mut String,
+                       css: &str,
+                       previous_token_type: TokenSerializationType,
+                       next_token_type: TokenSerializationType) {
+    if !css.is_empty() && previous_token_type.needs_separator_when_before(next_token_type) {
+        substituted.push_str("/**/");
+    }
+    substituted.push_str(css);
+}
+
+/// Substitute a single custom property declaration, and memoize the result
+/// in `substituted_map` so that diamond references are only resolved once.
+///
+/// `previous_token_type` is the serialization type of whatever was last
+/// written to `substituted` (if any), so that a separator can be inserted
+/// before this value if needed. Returns the serialization types of the
+/// first and last token of the (possibly cached) resolved value.
 fn substitute_one(name: &Atom,
                   value: &BorrowedValue,
-                  custom_properties: &HashMap<&Atom, BorrowedValue>,
+                  custom_properties: &OrderedMap<&Atom, BorrowedValue>,
                   substituted: Option<&mut String>,
-                  substituted_map: &mut HashMap<Atom, String>,
-                  invalid: &mut HashSet<Atom>)
-                  -> Result<(), ()> {
-    if let Some(value) = substituted_map.get(name) {
+                  previous_token_type: TokenSerializationType,
+                  substituted_map: &mut CustomPropertiesMap,
+                  invalid: &mut PrecomputedHashSet<Atom>)
+                  -> Result<(TokenSerializationType, TokenSerializationType), ()> {
+    if let Some(computed) = substituted_map.get(name) {
         if let Some(substituted) = substituted {
-            substituted.push_str(value)
+            push_with_separator(substituted, &computed.css, previous_token_type,
+                                 computed.first_token_type);
         }
-        return Ok(())
+        return Ok((computed.first_token_type, computed.last_token_type))
     }
 
     if invalid.contains(name) {
         return Err(());
     }
-    let value = if let Some(references) = value.references {
+    let computed = if let Some(references) = value.references {
         if !references.is_empty() {
-            let mut substituted = String::new();
+            let mut css = String::new();
             let mut input = Parser::new(&value.value);
             let mut start = input.position();
-            if substitute_block(
-                custom_properties, &mut input, &mut start, &mut substituted,
-                substituted_map, invalid,
-            ).is_err() {
-                invalid.insert(name.clone());
-                return Err(())
+            let result = substitute_block(
+                custom_properties, &mut input, &mut start, &mut css,
+                TokenSerializationType::nothing(), substituted_map, invalid,
+            );
+            let last_written_type = match result {
+                Ok(t) => t,
+                Err(()) => {
+                    invalid.insert(name.clone());
+                    return Err(())
+                }
+            };
+            let tail = input.slice_from(start);
+            if !tail.is_empty() {
+                let (tail_first, _) = tokenize_bounds(tail);
+                push_with_separator(&mut css, tail, last_written_type, tail_first);
+            }
+            let (first_token_type, last_token_type) = tokenize_bounds(&css);
+            ComputedValue {
+                css: css,
+                first_token_type: first_token_type,
+                last_token_type: last_token_type,
             }
-            substituted.push_str(input.slice_from(start));
-            substituted
         } else {
-            value.value.to_owned()
+            ComputedValue {
+                css: value.value.to_owned(),
+                first_token_type: value.first_token_type,
+                last_token_type: value.last_token_type,
+            }
         }
     } else {
-        value.value.to_owned()
+        ComputedValue {
+            css: value.value.to_owned(),
+            first_token_type: value.first_token_type,
+            last_token_type: value.last_token_type,
+        }
     };
     if let Some(substituted) = substituted {
-        substituted.push_str(&value)
+        push_with_separator(substituted, &computed.css, previous_token_type,
+                             computed.first_token_type);
     }
-    substituted_map.insert(name.clone(), value);
-    Ok(())
+    let result = (computed.first_token_type, computed.last_token_type);
+    substituted_map.insert(name.clone(), computed);
+    Ok(result)
 }
 
-fn substitute_block(custom_properties: &HashMap<&Atom, BorrowedValue>,
+/// Replace `var()` references in the remainder of `input` with their
+/// substituted values, writing the result into `substituted`.
+///
+/// Returns the serialization type of the last token written to
+/// `substituted`, which the caller threads back in on its next call so
+/// that a `/**/` separator can be inserted if needed.
+fn substitute_block(custom_properties: &OrderedMap<&Atom, BorrowedValue>,
                     input: &mut Parser,
                     start: &mut SourcePosition,
                     substituted: &mut String,
-                    substituted_map: &mut HashMap<Atom, String>,
-                    invalid: &mut HashSet<Atom>)
-                    -> Result<(), ()> {
+                    mut previous_token_type: TokenSerializationType,
+                    substituted_map: &mut CustomPropertiesMap,
+                    invalid: &mut PrecomputedHashSet<Atom>)
+                    -> Result<TokenSerializationType, ()> {
     while let Ok(token) = input.next() {
         match token {
             Token::Function(ref name) if name == "var" => {
-                substituted.push_str(input.slice_from(*start));
+                let prefix = input.slice_from(*start);
+                if !prefix.is_empty() {
+                    let (prefix_first, prefix_last) = tokenize_bounds(prefix);
+                    push_with_separator(substituted, prefix, previous_token_type, prefix_first);
+                    previous_token_type = prefix_last;
+                }
                 try!(input.parse_nested_block(|input| {
                     let name = input.expect_ident().unwrap();
                     debug_assert!(name.starts_with("--"));
                     let name = Atom::from_slice(&name[2..]);
 
                     if let Some(value) = custom_properties.get(&name) {
-                        try!(substitute_one(
+                        let (_, last_token_type) = try!(substitute_one(
                             &name, value, custom_properties,
-                            Some(substituted), substituted_map, invalid));
+                            Some(substituted), previous_token_type, substituted_map, invalid));
+                        previous_token_type = last_token_type;
                         // Skip over the fallback, as `parse_nested_block` would return `Err`
                         // if we don’t consume all of `input`.
                         // FIXME: Add a specialized method to cssparser to do this with less work.
@@ -287,10 +667,16 @@ fn substitute_block(custom_properties: &HashMap<&Atom, BorrowedValue>,
                     } else {
                         try!(input.expect_comma());
                         let mut start = input.position();
-                        try!(substitute_block(
+                        previous_token_type = try!(substitute_block(
                             custom_properties, input, &mut start, substituted,
-                            substituted_map, invalid));
-                        substituted.push_str(input.slice_from(start));
+                            previous_token_type, substituted_map, invalid));
+                        let fallback_tail = input.slice_from(start);
+                        if !fallback_tail.is_empty() {
+                            let (tail_first, tail_last) = tokenize_bounds(fallback_tail);
+                            push_with_separator(substituted, fallback_tail, previous_token_type,
+                                                tail_first);
+                            previous_token_type = tail_last;
+                        }
                     }
                     Ok(())
                 }));
@@ -301,12 +687,13 @@ fn substitute_block(custom_properties: &HashMap<&Atom, BorrowedValue>,
             Token::ParenthesisBlock |
             Token::CurlyBracketBlock |
             Token::SquareBracketBlock => {
-                try!(input.parse_nested_block(|input| substitute_block(
-                    custom_properties, input, start, substituted, substituted_map, invalid)));
+                previous_token_type = try!(input.parse_nested_block(|input| substitute_block(
+                    custom_properties, input, start, substituted, previous_token_type,
+                    substituted_map, invalid)));
             }
 
             _ => {}
         }
     }
-    Ok(())
+    Ok(previous_token_type)
 }